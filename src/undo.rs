@@ -1,8 +1,12 @@
 // Undo.rs - Utilities for undoing, redoing and storing events
 use crate::{Direction, Position, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 // Enum for the the types of banks
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BankType {
     Line,   // For holding lines from the document
     Cursor, // For holding cursor positions
@@ -19,6 +23,8 @@ pub enum Event {
     InsertLineBelow(usize),                // Return on the end of line
     Deletion(Position, char),              // Delete from middle
     Insertion(Position, char),             // Insert character
+    InsertionRun(Position, String),        // A run of adjacent insertions, coalesced
+    DeletionRun(Position, String),         // A run of adjacent deletions, coalesced
     DeleteLine(usize, Box<Row>),           // For deleting a line
     UpdateLine(usize, Box<Row>, Box<Row>), // For holding entire line updates
     MoveCursor(i128, Direction),           // For moving the cursor
@@ -37,43 +43,713 @@ pub enum Event {
     PrevTab,                               // Previous tab
 }
 
-// A struct for holding all the events taken by the user
-#[derive(Debug)]
+// Controls when a run of consecutive character edits is broken into its own undo step
+#[derive(Debug, Clone, Copy)]
+pub enum MergeBoundary {
+    Whitespace,    // Break the run whenever whitespace is typed or deleted
+    Punctuation,   // Break the run on whitespace or punctuation, for word-granular undo
+    Timeout(u128), // Break the run after this many milliseconds of inactivity
+}
+
+// What a hook callback asks the stack to do after observing an event
+pub enum HookResult {
+    Continue,            // Allow the event to proceed as normal
+    Cancel,               // Abort the event, e.g. cancel a pending `Quit` or `Save`
+    Enqueue(Vec<Event>), // Allow the event, and additionally queue these follow-up events
+}
+
+// A callback subscribed to a hook, seeing the triggering event and the uncommitted patch;
+// for a pushed event that patch is as it stood before the event was added
+pub type HookCallback = Box<dyn FnMut(&Event, &[Event]) -> HookResult>;
+
+// A single committed patch, living at a particular point in the history tree
+#[derive(Debug, Clone)]
+struct Node {
+    patch: Vec<Event>,     // The events that move from the parent to this node
+    parent: Option<usize>, // The node this one branched from (None for the root)
+    children: Vec<usize>,  // Branches that diverge from this node, oldest first
+    seq: u64,              // Global commit order, used to rebase remote patches
+}
+
+// On-disk mirror of `Position`'s (x, y) fields. `Position` is defined outside this
+// module and isn't guaranteed to derive `Serialize`/`Deserialize` itself, so the sidecar
+// format carries its own copy of the two fields rather than depending on that.
+#[derive(Serialize, Deserialize)]
+struct PersistedPosition {
+    x: usize,
+    y: usize,
+}
+
+impl From<Position> for PersistedPosition {
+    fn from(pos: Position) -> Self {
+        Self { x: pos.x, y: pos.y }
+    }
+}
+
+impl From<PersistedPosition> for Position {
+    fn from(pos: PersistedPosition) -> Self {
+        Position { x: pos.x, y: pos.y }
+    }
+}
+
+// On-disk mirror of `Event`, for the same reason as `PersistedPosition`. Variants that
+// carry a `Row` or `Direction` have no safe on-disk representation here (neither type is
+// guaranteed to derive `Serialize`/`Deserialize`), so they're left out entirely; such
+// events are dropped from a patch when it's persisted rather than blocking the rest of
+// the history from saving (see `PersistedEvent::from_event`).
+#[derive(Serialize, Deserialize)]
+enum PersistedEvent {
+    Store(BankType, usize),
+    Load(BankType, usize),
+    SpliceUp(PersistedPosition),
+    SplitDown(PersistedPosition),
+    InsertLineAbove(usize),
+    InsertLineBelow(usize),
+    Deletion(PersistedPosition, char),
+    Insertion(PersistedPosition, char),
+    InsertionRun(PersistedPosition, String),
+    DeletionRun(PersistedPosition, String),
+    GotoCursor(PersistedPosition),
+    New,
+    Open(Option<String>),
+    Save(Option<String>, bool),
+    SaveAll,
+    Undo,
+    Redo,
+    Commit,
+    Quit(bool),
+    QuitAll(bool),
+    NextTab,
+    PrevTab,
+}
+
+impl PersistedEvent {
+    // `None` for the handful of variants that carry a `Row` or `Direction`
+    fn from_event(event: &Event) -> Option<Self> {
+        Some(match event.clone() {
+            Event::Store(bank, index) => Self::Store(bank, index),
+            Event::Load(bank, index) => Self::Load(bank, index),
+            Event::SpliceUp(pos) => Self::SpliceUp(pos.into()),
+            Event::SplitDown(pos) => Self::SplitDown(pos.into()),
+            Event::InsertLineAbove(y) => Self::InsertLineAbove(y),
+            Event::InsertLineBelow(y) => Self::InsertLineBelow(y),
+            Event::Deletion(pos, ch) => Self::Deletion(pos.into(), ch),
+            Event::Insertion(pos, ch) => Self::Insertion(pos.into(), ch),
+            Event::InsertionRun(pos, run) => Self::InsertionRun(pos.into(), run),
+            Event::DeletionRun(pos, run) => Self::DeletionRun(pos.into(), run),
+            Event::GotoCursor(pos) => Self::GotoCursor(pos.into()),
+            Event::New => Self::New,
+            Event::Open(path) => Self::Open(path),
+            Event::Save(path, force) => Self::Save(path, force),
+            Event::SaveAll => Self::SaveAll,
+            Event::Undo => Self::Undo,
+            Event::Redo => Self::Redo,
+            Event::Commit => Self::Commit,
+            Event::Quit(force) => Self::Quit(force),
+            Event::QuitAll(force) => Self::QuitAll(force),
+            Event::NextTab => Self::NextTab,
+            Event::PrevTab => Self::PrevTab,
+            Event::DeleteLine(..) | Event::UpdateLine(..) | Event::MoveCursor(..) | Event::Overwrite(..) => {
+                return None
+            }
+        })
+    }
+}
+
+impl From<PersistedEvent> for Event {
+    fn from(event: PersistedEvent) -> Self {
+        match event {
+            PersistedEvent::Store(bank, index) => Event::Store(bank, index),
+            PersistedEvent::Load(bank, index) => Event::Load(bank, index),
+            PersistedEvent::SpliceUp(pos) => Event::SpliceUp(pos.into()),
+            PersistedEvent::SplitDown(pos) => Event::SplitDown(pos.into()),
+            PersistedEvent::InsertLineAbove(y) => Event::InsertLineAbove(y),
+            PersistedEvent::InsertLineBelow(y) => Event::InsertLineBelow(y),
+            PersistedEvent::Deletion(pos, ch) => Event::Deletion(pos.into(), ch),
+            PersistedEvent::Insertion(pos, ch) => Event::Insertion(pos.into(), ch),
+            PersistedEvent::InsertionRun(pos, run) => Event::InsertionRun(pos.into(), run),
+            PersistedEvent::DeletionRun(pos, run) => Event::DeletionRun(pos.into(), run),
+            PersistedEvent::GotoCursor(pos) => Event::GotoCursor(pos.into()),
+            PersistedEvent::New => Event::New,
+            PersistedEvent::Open(path) => Event::Open(path),
+            PersistedEvent::Save(path, force) => Event::Save(path, force),
+            PersistedEvent::SaveAll => Event::SaveAll,
+            PersistedEvent::Undo => Event::Undo,
+            PersistedEvent::Redo => Event::Redo,
+            PersistedEvent::Commit => Event::Commit,
+            PersistedEvent::Quit(force) => Event::Quit(force),
+            PersistedEvent::QuitAll(force) => Event::QuitAll(force),
+            PersistedEvent::NextTab => Event::NextTab,
+            PersistedEvent::PrevTab => Event::PrevTab,
+        }
+    }
+}
+
+// On-disk mirror of `Node`, with `patch` narrowed to the events `PersistedEvent` supports
+#[derive(Serialize, Deserialize)]
+struct PersistedNode {
+    patch: Vec<PersistedEvent>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    seq: u64,
+}
+
+impl From<&Node> for PersistedNode {
+    fn from(node: &Node) -> Self {
+        Self {
+            patch: node.patch.iter().filter_map(PersistedEvent::from_event).collect(),
+            parent: node.parent,
+            children: node.children.clone(),
+            seq: node.seq,
+        }
+    }
+}
+
+impl From<PersistedNode> for Node {
+    fn from(node: PersistedNode) -> Self {
+        Self {
+            patch: node.patch.into_iter().map(Event::from).collect(),
+            parent: node.parent,
+            children: node.children,
+            seq: node.seq,
+        }
+    }
+}
+
+// The on-disk form of an EventStack, sidecar-saved next to the document it belongs to
+#[derive(Serialize, Deserialize)]
+struct HistoryFile {
+    hash: u64, // Hash of the document contents this history was recorded against
+    nodes: HashMap<usize, PersistedNode>,
+    root: usize,
+    current: usize,
+    saved: Option<usize>, // The node id that was the saved position, if any
+}
+
+// A tree of branches holding all the events taken by the user, keyed by id rather than
+// a `Vec` so pruning (see `max_len`/`max_bytes`) can drop nodes without renumbering
 pub struct EventStack {
-    history: Vec<Vec<Event>>,  // For storing the history of events
-    current_patch: Vec<Event>, // For storing the current group
+    nodes: HashMap<usize, Node>, // Every live node, indexed by id
+    root: usize,                 // The oldest node still retained; has no parent
+    current: usize,              // The node id representing where we currently are
+    next_id: usize,               // The id to assign to the next node that's created
+    current_patch: Vec<Event>, // For storing the current uncommitted group
+    boundary: MergeBoundary,   // When a run of character edits is split into separate steps
+    last_edit: Option<Instant>, // When the last event of any kind was pushed, for the timeout policy
+    saved: Option<usize>,       // The node id we were at when the file was last saved
+    on_saved_change: Option<Box<dyn FnMut(bool)>>, // Fired when the modified/clean boundary is crossed
+    site: u64,                  // This session's site id, for deterministic OT tie-breaking
+    next_seq: u64,               // The seq to assign to the next committed node
+    hooks: HashMap<String, Vec<HookCallback>>, // Subscribers, keyed by hook name e.g. "on save"
+    visited: Vec<usize>,         // Node ids in the order they were last visited, oldest first
+    max_len: Option<usize>,      // Cap on the number of retained nodes besides the root, oldest/least-used pruned first
+    max_bytes: Option<usize>,    // Cap on the approximate total size of retained patches
+    evicted_banks: Vec<(BankType, usize)>, // Store/Load bank refs invalidated by pruning
+}
+
+impl std::fmt::Debug for EventStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStack")
+            .field("nodes", &self.nodes)
+            .field("current", &self.current)
+            .field("current_patch", &self.current_patch)
+            .field("boundary", &self.boundary)
+            .field("saved", &self.saved)
+            .field("site", &self.site)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for EventStack {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // Methods for the EventStack
 impl EventStack {
     pub fn new() -> Self {
-        // Initialise an Event stack
+        // Initialise an Event stack with just a root node, considered saved
+        Self::new_with_site(0)
+    }
+    // Initialise an Event stack tagged with `site`, the id used to break insertion ties
+    // deterministically when rebasing patches from other sites (see `transform`)
+    pub fn new_with_site(site: u64) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            0,
+            Node {
+                patch: vec![],
+                parent: None,
+                children: vec![],
+                seq: 0,
+            },
+        );
         Self {
-            history: vec![],
+            nodes,
+            root: 0,
+            current: 0,
+            next_id: 1,
             current_patch: vec![],
+            boundary: MergeBoundary::Whitespace,
+            last_edit: None,
+            saved: Some(0),
+            on_saved_change: None,
+            site,
+            next_seq: 1,
+            hooks: HashMap::new(),
+            visited: vec![0],
+            max_len: None,
+            max_bytes: None,
+            evicted_banks: vec![],
+        }
+    }
+    // Cap the number of retained nodes; see `enforce_capacity` for the pruning order
+    pub fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+        self.enforce_capacity();
+    }
+    // Cap the approximate total size of retained patches; see `set_max_len` for the pruning order
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.enforce_capacity();
+    }
+    // Drain the `(BankType, index)` pairs referenced by `Store`/`Load` events that pruning
+    // has discarded, so the caller can invalidate or compact the matching bank storage
+    pub fn take_evicted_banks(&mut self) -> Vec<(BankType, usize)> {
+        std::mem::take(&mut self.evicted_banks)
+    }
+    fn enforce_capacity(&mut self) {
+        while self.over_capacity() {
+            if let Some(victim) = self.least_recently_visited_leaf() {
+                self.prune_leaf(victim);
+            } else if let Some(new_root) = self.next_ancestor_of_current() {
+                self.prune_root_chain(new_root);
+            } else {
+                break; // Nothing left to drop without losing the current position itself
+            }
+        }
+    }
+    fn over_capacity(&self) -> bool {
+        let retained = self.nodes.len().saturating_sub(1); // exclude the root
+        self.max_len.is_some_and(|max| retained > max)
+            || self.max_bytes.is_some_and(|max| self.total_patch_bytes() > max)
+    }
+    fn total_patch_bytes(&self) -> usize {
+        self.nodes.values().map(|node| Self::patch_bytes(&node.patch)).sum()
+    }
+    // A rough size estimate; `Row`'s own heap data isn't introspectable from here, so cloned
+    // rows are counted at a flat per-row cost rather than their true byte size
+    fn patch_bytes(patch: &[Event]) -> usize {
+        patch
+            .iter()
+            .map(|event| match event {
+                Event::InsertionRun(_, run) | Event::DeletionRun(_, run) => run.len(),
+                Event::DeleteLine(_, row) => std::mem::size_of_val(&**row),
+                Event::UpdateLine(_, before, after) => {
+                    std::mem::size_of_val(&**before) + std::mem::size_of_val(&**after)
+                }
+                Event::Overwrite(before, after) => {
+                    (before.len() + after.len()) * std::mem::size_of::<Row>()
+                }
+                _ => std::mem::size_of::<Event>(),
+            })
+            .sum()
+    }
+    // The prunable leaf (not the root, not `current`) that was visited longest ago, if any
+    fn least_recently_visited_leaf(&self) -> Option<usize> {
+        self.nodes
+            .iter()
+            .filter(|(&id, node)| id != self.root && id != self.current && node.children.is_empty())
+            .min_by_key(|(&id, _)| self.visited.iter().position(|&v| v == id).unwrap_or(0))
+            .map(|(&id, _)| id)
+    }
+    fn prune_leaf(&mut self, id: usize) {
+        self.collect_evicted_banks(id);
+        if let Some(node) = self.nodes.remove(&id) {
+            if let Some(parent) = node.parent {
+                if let Some(parent_node) = self.nodes.get_mut(&parent) {
+                    parent_node.children.retain(|&child| child != id);
+                }
+            }
+        }
+        self.visited.retain(|&v| v != id);
+    }
+    // The child of `root` on the path to `current`, i.e. the next node `root` could become
+    fn next_ancestor_of_current(&self) -> Option<usize> {
+        let path = self.path_from_root(self.current);
+        (path.len() > 1).then_some(path[1])
+    }
+    // Drop the current root, adopting `new_root` in its place, trading away the ability to
+    // undo any further back in exchange for bounded memory use
+    fn prune_root_chain(&mut self, new_root: usize) {
+        self.collect_evicted_banks(self.root);
+        self.nodes.remove(&self.root);
+        self.visited.retain(|&v| v != self.root);
+        if let Some(node) = self.nodes.get_mut(&new_root) {
+            node.parent = None;
+        }
+        self.root = new_root;
+    }
+    fn collect_evicted_banks(&mut self, id: usize) {
+        let Some(node) = self.nodes.get(&id) else {
+            return;
+        };
+        for event in &node.patch {
+            if let Event::Store(bank, index) | Event::Load(bank, index) = event {
+                self.evicted_banks.push((bank.clone(), *index));
+            }
+        }
+    }
+    // Subscribe to events of a given kind, e.g. "on save", "on insert", "on quit". The
+    // callback sees the triggering event and the uncommitted patch, and can cancel the
+    // event or enqueue follow-up events, e.g. for autosave-on-idle or format-on-save.
+    pub fn subscribe(&mut self, kind: impl Into<String>, callback: HookCallback) {
+        self.hooks.entry(kind.into()).or_default().push(callback);
+    }
+    // Run every callback subscribed to `kind`, aggregating their verdicts
+    fn fire(&mut self, kind: &str, event: &Event) -> HookResult {
+        let Some(mut callbacks) = self.hooks.remove(kind) else {
+            return HookResult::Continue;
+        };
+        let mut cancelled = false;
+        let mut enqueued = vec![];
+        for callback in &mut callbacks {
+            match callback(event, &self.current_patch) {
+                HookResult::Cancel => cancelled = true,
+                HookResult::Enqueue(mut events) => enqueued.append(&mut events),
+                HookResult::Continue => {}
+            }
+        }
+        self.hooks.insert(kind.to_string(), callbacks);
+        if cancelled {
+            HookResult::Cancel
+        } else if enqueued.is_empty() {
+            HookResult::Continue
+        } else {
+            HookResult::Enqueue(enqueued)
         }
     }
-    pub fn push(&mut self, event: Event) {
-        // Add an event to the event stack
-        self.current_patch.insert(0, event);
+    // Register a callback fired whenever `is_modified` flips, e.g. to toggle a `*` in a tab title
+    pub fn on_saved_change(&mut self, callback: impl FnMut(bool) + 'static) {
+        self.on_saved_change = Some(Box::new(callback));
+    }
+    // Mark the current position in the history as the saved state, e.g. after `Event::Save` succeeds
+    pub fn mark_saved(&mut self) {
+        self.set_saved(Some(self.current));
+    }
+    fn set_saved(&mut self, saved: Option<usize>) {
+        let was_modified = self.is_modified();
+        self.saved = saved;
+        self.notify_if_changed(was_modified);
+    }
+    // Whether the current position differs from the last saved position
+    pub fn is_modified(&self) -> bool {
+        self.saved != Some(self.current)
+    }
+    // Move to `node`, firing the saved-change callback if this crosses the modified/clean boundary
+    fn set_current(&mut self, node: usize) {
+        let was_modified = self.is_modified();
+        self.current = node;
+        self.visited.retain(|&v| v != node);
+        self.visited.push(node);
+        self.notify_if_changed(was_modified);
+    }
+    fn notify_if_changed(&mut self, was_modified: bool) {
+        let is_modified = self.is_modified();
+        if was_modified != is_modified {
+            if let Some(callback) = &mut self.on_saved_change {
+                callback(is_modified);
+            }
+        }
+    }
+    // Choose when runs of character edits are split into separate undo steps
+    pub fn set_boundary(&mut self, boundary: MergeBoundary) {
+        self.boundary = boundary;
+    }
+    // Add an event to the event stack, folding it into the current run of insertions or
+    // deletions if it is adjacent to the one before it. Returns false if a subscribed hook
+    // cancelled the event, in which case it was not added.
+    pub fn push(&mut self, event: Event) -> bool {
+        let enqueued = match self.fire(event_kind(&event), &event) {
+            HookResult::Cancel => return false,
+            HookResult::Enqueue(enqueued) => enqueued,
+            HookResult::Continue => vec![],
+        };
+        let merged = !self.current_patch.is_empty()
+            && !self.idle_timeout_elapsed()
+            && self.try_merge(&event);
+        if !merged {
+            self.current_patch.insert(0, event);
+        }
+        self.last_edit = Some(Instant::now());
+        for event in enqueued {
+            self.push(event);
+        }
+        true
+    }
+    // Attempt to fold `event` into the run at the front of `current_patch`,
+    // replacing it in place. Returns whether the merge happened.
+    fn try_merge(&mut self, event: &Event) -> bool {
+        let front = self.current_patch[0].clone();
+        let merged = match (front, event) {
+            (Event::Insertion(start, first), Event::Insertion(pos, ch))
+                if pos.y == start.y
+                    && pos.x == start.x + 1
+                    && !self.breaks_run(first)
+                    && !self.breaks_run(*ch) =>
+            {
+                Some(Event::InsertionRun(start, format!("{first}{ch}")))
+            }
+            (Event::InsertionRun(start, mut run), Event::Insertion(pos, ch))
+                if pos.y == start.y
+                    && pos.x == start.x + run.chars().count()
+                    && !self.breaks_run(*ch) =>
+            {
+                run.push(*ch);
+                Some(Event::InsertionRun(start, run))
+            }
+            // Backspacing walks backwards, so each new deletion leads the run
+            (Event::Deletion(start, first), Event::Deletion(pos, ch))
+                if pos.y == start.y
+                    && pos.x + 1 == start.x
+                    && !self.breaks_run(first)
+                    && !self.breaks_run(*ch) =>
+            {
+                Some(Event::DeletionRun(*pos, format!("{ch}{first}")))
+            }
+            (Event::DeletionRun(start, mut run), Event::Deletion(pos, ch))
+                if pos.y == start.y && pos.x + 1 == start.x && !self.breaks_run(*ch) =>
+            {
+                run.insert(0, *ch);
+                Some(Event::DeletionRun(*pos, run))
+            }
+            _ => None,
+        };
+        match merged {
+            Some(event) => {
+                self.current_patch[0] = event;
+                true
+            }
+            None => false,
+        }
+    }
+    // Whether `ch` should start a fresh undo step rather than extend the current run
+    fn breaks_run(&self, ch: char) -> bool {
+        match self.boundary {
+            MergeBoundary::Whitespace => ch.is_whitespace(),
+            MergeBoundary::Punctuation => ch.is_whitespace() || ch.is_ascii_punctuation(),
+            MergeBoundary::Timeout(_) => false,
+        }
+    }
+    // Whether the idle-timeout boundary has elapsed since the last pushed event
+    fn idle_timeout_elapsed(&self) -> bool {
+        if let MergeBoundary::Timeout(limit) = self.boundary {
+            self.last_edit.is_some_and(|t| t.elapsed().as_millis() > limit)
+        } else {
+            false
+        }
     }
     pub fn append(&mut self, patch: Vec<Event>) {
-        self.history.push(patch);
+        // Branch off the current node with an already-formed patch
+        self.branch(patch);
     }
     pub fn pop(&mut self) -> Option<Vec<Event>> {
-        // Take a patch off the event stack
-        self.history.pop()
+        // Undo: walk up to the parent, handing back the patch that got us here
+        let parent = self.nodes[&self.current].parent?;
+        let patch = self.nodes[&self.current].patch.clone();
+        self.set_current(parent);
+        Some(patch)
+    }
+    pub fn redo(&mut self) -> Option<Vec<Event>> {
+        // Redo: descend into the most recently created branch
+        let child = *self.nodes[&self.current].children.last()?;
+        self.set_current(child);
+        Some(self.nodes[&child].patch.clone())
     }
     pub fn empty(&mut self) {
-        // Empty the stack
-        self.history.clear();
+        // Empty the stack, leaving just the root node
+        self.nodes.retain(|&id, _| id == self.root);
+        if let Some(root) = self.nodes.get_mut(&self.root) {
+            root.children.clear();
+        }
+        self.visited.clear();
+        self.evicted_banks.clear();
+        self.set_current(self.root);
     }
-    pub fn commit(&mut self) {
-        // Commit patch to history
-        if !self.current_patch.is_empty() {
-            self.history.push(self.current_patch.clone());
-            self.current_patch.clear();
+    // Commit patch to history as a new branch off the current node. Returns false if a
+    // subscribed hook cancelled the commit, e.g. aborting a pending `Quit` or `Save`.
+    pub fn commit(&mut self) -> bool {
+        if self.current_patch.is_empty() {
+            return true;
         }
+        if let Some(event) = self.current_patch.first().cloned() {
+            if matches!(self.fire("on commit", &event), HookResult::Cancel) {
+                return false;
+            }
+        }
+        let patch = std::mem::take(&mut self.current_patch);
+        self.branch(patch);
+        true
+    }
+    // Create a new node holding `patch`, branching off the current node
+    fn branch(&mut self, patch: Vec<Event>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.nodes.insert(
+            id,
+            Node {
+                patch,
+                parent: Some(self.current),
+                children: vec![],
+                seq,
+            },
+        );
+        self.nodes.get_mut(&self.current).unwrap().children.push(id);
+        self.set_current(id);
+        self.enforce_capacity();
+        id
+    }
+    // The id of the node the stack is currently positioned at
+    pub fn current(&self) -> usize {
+        self.current
+    }
+    // Every node where the history diverges into more than one branch
+    pub fn branches(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.children.len() > 1)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+    // The chain of node ids from the root down to `node`, root first
+    fn path_from_root(&self, node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while let Some(parent) = self.nodes[path.last().unwrap()].parent {
+            path.push(parent);
+        }
+        path.reverse();
+        path
+    }
+    // Compute the shortest undo/redo walk from the current node to `target`,
+    // returning the events to apply, in order
+    pub fn goto(&mut self, target: usize) -> Vec<Event> {
+        let from = self.path_from_root(self.current);
+        let to = self.path_from_root(target);
+        // The lowest common ancestor is where the two paths from the root diverge
+        let shared = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+        let lca = shared.saturating_sub(1);
+        let mut events = vec![];
+        // Undo back up to the common ancestor. Patches are stored newest-event-first, so
+        // walking each one in stored order already undoes its most recent edit first.
+        // Events `reverse` has no inverse for (bank/cursor/document events) aren't
+        // droppable: fall back to the original event rather than silently losing it.
+        for &node in from[lca + 1..].iter().rev() {
+            for event in self.nodes[&node].patch.iter().cloned() {
+                events.push(reverse(event.clone()).unwrap_or(event));
+            }
+        }
+        // Redo back down to the target, applying each patch in the order it actually
+        // happened (patches are stored newest-event-first; see `push`)
+        for &node in &to[lca + 1..] {
+            events.extend(self.nodes[&node].patch.iter().rev().cloned());
+        }
+        self.set_current(target);
+        events
+    }
+    // Write the undo graph to a sidecar file next to `path`, keyed by a hash of `contents`
+    // so a history file that no longer matches the document is rejected rather than misapplied
+    pub fn save_history(&self, path: &Path, contents: &str) -> std::io::Result<()> {
+        let nodes = self.nodes.iter().map(|(&id, node)| (id, PersistedNode::from(node))).collect();
+        let file = HistoryFile {
+            hash: Self::hash_contents(contents),
+            nodes,
+            root: self.root,
+            current: self.current,
+            saved: self.saved,
+        };
+        let json = serde_json::to_string(&file)?;
+        std::fs::write(Self::sidecar_path(path), json)
+    }
+    // Restore the undo graph from a sidecar file, if one exists and its hash matches `contents`.
+    // Used on `Event::Open` so a previous session's undo history carries over.
+    pub fn load_history(&mut self, path: &Path, contents: &str) -> bool {
+        let Ok(raw) = std::fs::read_to_string(Self::sidecar_path(path)) else {
+            return false;
+        };
+        let Ok(file) = serde_json::from_str::<HistoryFile>(&raw) else {
+            return false;
+        };
+        if file.hash != Self::hash_contents(contents) {
+            return false;
+        }
+        let was_modified = self.is_modified();
+        self.next_seq = file.nodes.values().map(|node| node.seq).max().unwrap_or(0) + 1;
+        self.next_id = file.nodes.keys().max().copied().unwrap_or(0) + 1;
+        self.nodes = file.nodes.into_iter().map(|(id, node)| (id, Node::from(node))).collect();
+        self.root = file.root;
+        self.current_patch.clear();
+        self.current = file.current;
+        // Approximate visit order with commit order, since that's all a reloaded history
+        // has to go on; `current` goes last so it isn't the first thing pruning evicts
+        let mut visited: Vec<usize> = self.nodes.keys().copied().filter(|&id| id != file.current).collect();
+        visited.sort_unstable_by_key(|id| self.nodes[id].seq);
+        visited.push(file.current);
+        self.visited = visited;
+        self.saved = file.saved;
+        self.notify_if_changed(was_modified);
+        true
+    }
+    fn sidecar_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".oxundo");
+        PathBuf::from(name)
+    }
+    // FNV-1a: unlike `DefaultHasher`, this is stable across Rust versions and platforms,
+    // which matters here since the hash is persisted on disk and compared against later
+    fn hash_contents(contents: &str) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
+        contents.bytes().fold(OFFSET_BASIS, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME))
+    }
+    // Rebase an incoming remote patch against local patches committed since `base_seq`,
+    // then commit the result as a new node so it joins this session's history too
+    pub fn apply_remote(&mut self, patch: Vec<Event>, base_seq: u64, remote_site: u64) -> Vec<Event> {
+        let local_site = self.site;
+        let mut patch = patch;
+        for node in self.path_from_root(self.current) {
+            if self.nodes[&node].seq <= base_seq {
+                continue;
+            }
+            // Patches are stored newest-event-first (see `push`), so walk them in the
+            // order they were actually applied when rebasing against them
+            for local_event in self.nodes[&node].patch.iter().rev() {
+                patch = patch
+                    .into_iter()
+                    .map(|event| transform(event, local_event, remote_site, local_site))
+                    .collect();
+            }
+        }
+        self.branch(patch.clone());
+        patch
+    }
+}
+
+// The hook name an event fires under when pushed, e.g. for `EventStack::subscribe`
+fn event_kind(event: &Event) -> &'static str {
+    match event {
+        Event::Insertion(..) | Event::InsertionRun(..) => "on insert",
+        Event::Deletion(..) | Event::DeletionRun(..) => "on delete",
+        Event::Save(..) | Event::SaveAll => "on save",
+        Event::Quit(..) | Event::QuitAll(..) => "on quit",
+        Event::Open(..) => "on open",
+        Event::New => "on new",
+        _ => "on event",
     }
 }
 
@@ -87,9 +763,293 @@ pub fn reverse(before: Event) -> Option<Event> {
         Event::InsertLineBelow(y) => Event::DeleteLine(y.saturating_add(1), Box::new(Row::from(""))),
         Event::Deletion(pos, ch) => Event::Insertion(pos, ch),
         Event::Insertion(pos, ch) => Event::Deletion(pos, ch),
+        Event::InsertionRun(pos, run) => Event::DeletionRun(pos, run),
+        Event::DeletionRun(pos, run) => Event::InsertionRun(pos, run),
         Event::DeleteLine(y, before) => Event::UpdateLine(y, Box::new(Row::from("")), before),
         Event::UpdateLine(y, before, after) => Event::UpdateLine(y, after, before),
         Event::Overwrite(before, after) => Event::Overwrite(after, before),
         _ => return None,
     })
 }
+
+// Adjust `event` so it still applies correctly to a document that has already had `applied`
+// performed on it. This is the core of operational transformation: it lets two sites apply
+// the same two concurrent edits in either order and still converge on the same document.
+// Same-position insertion ties are broken deterministically by comparing site ids.
+pub fn transform(event: Event, applied: &Event, event_site: u64, applied_site: u64) -> Event {
+    match applied {
+        Event::Insertion(at, _) => shift_for_insertion(event, *at, event_site, applied_site),
+        Event::InsertionRun(at, run) => (0..run.chars().count()).fold(event, |event, i| {
+            let at = Position { x: at.x + i, ..*at };
+            shift_for_insertion(event, at, event_site, applied_site)
+        }),
+        Event::Deletion(at, _) => shift_for_deletion(event, *at),
+        Event::DeletionRun(at, run) => {
+            (0..run.chars().count()).fold(event, |event, _| shift_for_deletion(event, *at))
+        }
+        Event::InsertLineAbove(y) => shift_rows(event, *y, 1),
+        // The new line lands at y + 1 (see `reverse`), so row y itself stays put
+        Event::InsertLineBelow(y) => shift_rows(event, y.saturating_add(1), 1),
+        Event::DeleteLine(y, _) => shift_rows(event, *y, -1),
+        _ => event,
+    }
+}
+
+// Shift `pos` right by one column if it sits at or after `at` on the same row, as a result
+// of a remote insertion there. Ties at the same column go to whichever site sorts first.
+fn shift_position_forward(pos: Position, at: Position, event_site: u64, applied_site: u64) -> Position {
+    if pos.y != at.y {
+        return pos;
+    }
+    if pos.x > at.x || (pos.x == at.x && applied_site <= event_site) {
+        Position { x: pos.x + 1, ..pos }
+    } else {
+        pos
+    }
+}
+
+// Shift `pos` left by one column if it sat after `at` on the same row, as a result of a
+// remote deletion there
+fn shift_position_backward(pos: Position, at: Position) -> Position {
+    if pos.y == at.y && pos.x > at.x {
+        Position { x: pos.x - 1, ..pos }
+    } else {
+        pos
+    }
+}
+
+fn shift_for_insertion(event: Event, at: Position, event_site: u64, applied_site: u64) -> Event {
+    map_position(event, |pos| shift_position_forward(pos, at, event_site, applied_site))
+}
+
+fn shift_for_deletion(event: Event, at: Position) -> Event {
+    map_position(event, |pos| shift_position_backward(pos, at))
+}
+
+// Apply `f` to the position carried by any event that references one; events with no
+// position (cursor moves, whole-line edits, document-level events) pass through untouched
+fn map_position(event: Event, f: impl Fn(Position) -> Position) -> Event {
+    match event {
+        Event::Insertion(pos, ch) => Event::Insertion(f(pos), ch),
+        Event::Deletion(pos, ch) => Event::Deletion(f(pos), ch),
+        Event::InsertionRun(pos, run) => Event::InsertionRun(f(pos), run),
+        Event::DeletionRun(pos, run) => Event::DeletionRun(f(pos), run),
+        Event::SpliceUp(pos) => Event::SpliceUp(f(pos)),
+        Event::SplitDown(pos) => Event::SplitDown(f(pos)),
+        Event::GotoCursor(pos) => Event::GotoCursor(f(pos)),
+        other => other,
+    }
+}
+
+// Shift the row-indexed events affected by a remote line insertion (`delta` = 1) or
+// deletion (`delta` = -1) at `at_row`, including the row component of positional events
+fn shift_rows(event: Event, at_row: usize, delta: i128) -> Event {
+    let shift = |y: usize| -> usize {
+        if y < at_row {
+            y
+        } else {
+            (y as i128 + delta).max(0) as usize
+        }
+    };
+    match event {
+        Event::InsertLineAbove(y) => Event::InsertLineAbove(shift(y)),
+        Event::InsertLineBelow(y) => Event::InsertLineBelow(shift(y)),
+        Event::DeleteLine(y, row) => Event::DeleteLine(shift(y), row),
+        Event::UpdateLine(y, before, after) => Event::UpdateLine(shift(y), before, after),
+        other => map_position(other, |pos| Position {
+            y: shift(pos.y),
+            ..pos
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Patches are stored newest-event-first, so a local patch of [Y, X] means X was typed
+    // before Y. Rebasing a remote insert against it must walk X then Y, not Y then X.
+    #[test]
+    fn apply_remote_rebases_against_local_patch_in_applied_order() {
+        let mut stack = EventStack::new_with_site(1);
+        let x = Event::Insertion(Position { x: 0, y: 0 }, 'X');
+        let y = Event::Insertion(Position { x: 1, y: 0 }, 'Y');
+        stack.append(vec![y, x]);
+
+        let remote = vec![Event::Insertion(Position { x: 0, y: 0 }, 'R')];
+        let rebased = stack.apply_remote(remote, 0, 2);
+
+        assert_eq!(rebased.len(), 1);
+        match &rebased[0] {
+            Event::Insertion(pos, ch) => {
+                assert_eq!((pos.x, pos.y, *ch), (2, 0, 'R'));
+            }
+            other => panic!("expected a rebased Insertion, got {other:?}"),
+        }
+    }
+
+    // Branch off root twice, each with a multi-event, non-coalescing patch, then goto()
+    // from one leaf to the other. The redo half must replay its patch in actual apply
+    // order (patches are stored newest-first), and the undo half must carry through an
+    // event with no reverse() (Store) rather than dropping it.
+    #[test]
+    fn goto_crosses_a_branch_point() {
+        let mut stack = EventStack::new();
+        stack.push(Event::Insertion(Position { x: 0, y: 0 }, 'x'));
+        stack.push(Event::Insertion(Position { x: 5, y: 0 }, 'y'));
+        stack.commit();
+        let first = stack.current();
+        stack.pop();
+        stack.push(Event::Store(BankType::Line, 0));
+        stack.push(Event::Insertion(Position { x: 1, y: 0 }, 'a'));
+        stack.commit();
+
+        let events = stack.goto(first);
+
+        assert_eq!(events.len(), 4);
+        match &events[0] {
+            Event::Deletion(pos, ch) => assert_eq!((pos.x, pos.y, *ch), (1, 0, 'a')),
+            other => panic!("expected the other branch's insert to be undone first, got {other:?}"),
+        }
+        assert!(matches!(&events[1], Event::Store(BankType::Line, 0)), "non-reversible event should be carried through, got {:?}", events[1]);
+        match &events[2] {
+            Event::Insertion(pos, ch) => assert_eq!((pos.x, pos.y, *ch), (0, 0, 'x')),
+            other => panic!("expected this branch's first insert to be redone before its second, got {other:?}"),
+        }
+        match &events[3] {
+            Event::Insertion(pos, ch) => assert_eq!((pos.x, pos.y, *ch), (5, 0, 'y')),
+            other => panic!("expected this branch's second insert to be redone last, got {other:?}"),
+        }
+    }
+
+    // Adjacent inserts coalesce into one run; reverse() turns that run into a matching
+    // DeletionRun rather than undoing it character by character.
+    #[test]
+    fn adjacent_inserts_coalesce_and_reverse_as_one_run() {
+        let mut stack = EventStack::new();
+        stack.push(Event::Insertion(Position { x: 0, y: 0 }, 'a'));
+        stack.push(Event::Insertion(Position { x: 1, y: 0 }, 'b'));
+        stack.commit();
+
+        let patch = stack.pop().expect("should have a node to undo back from");
+        assert_eq!(patch.len(), 1);
+        match &patch[0] {
+            Event::InsertionRun(pos, run) => {
+                assert_eq!((pos.x, pos.y, run.as_str()), (0, 0, "ab"));
+                match reverse(patch[0].clone()) {
+                    Some(Event::DeletionRun(rpos, rrun)) => {
+                        assert_eq!((rpos.x, rpos.y, rrun.as_str()), (0, 0, "ab"));
+                    }
+                    other => panic!("expected DeletionRun, got {other:?}"),
+                }
+            }
+            other => panic!("expected the two inserts to coalesce, got {other:?}"),
+        }
+    }
+
+    // A leading whitespace char must start a fresh step rather than being folded into
+    // the run that follows it (see try_merge's own-leading-char check)
+    #[test]
+    fn leading_whitespace_does_not_fold_into_the_following_run() {
+        let mut stack = EventStack::new();
+        stack.push(Event::Insertion(Position { x: 0, y: 0 }, 'a'));
+        stack.push(Event::Insertion(Position { x: 1, y: 0 }, ' '));
+        stack.push(Event::Insertion(Position { x: 2, y: 0 }, 'b'));
+        stack.commit();
+
+        let patch = stack.pop().expect("should have a node to undo back from");
+        assert_eq!(patch.len(), 3);
+    }
+
+    // is_modified()/on_saved_change should flip on commit and flip back on undo to
+    // the saved position
+    #[test]
+    fn saved_boundary_notifies_on_commit_and_undo() {
+        let mut stack = EventStack::new();
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let recorded = seen.clone();
+        stack.on_saved_change(move |modified| recorded.borrow_mut().push(modified));
+
+        assert!(!stack.is_modified());
+        stack.push(Event::Insertion(Position { x: 0, y: 0 }, 'a'));
+        stack.commit();
+        assert!(stack.is_modified());
+        stack.pop();
+        assert!(!stack.is_modified());
+
+        assert_eq!(*seen.borrow(), vec![true, false]);
+    }
+
+    // HookResult::Cancel must stop the event from being added at all
+    #[test]
+    fn cancel_hook_prevents_push() {
+        let mut stack = EventStack::new();
+        stack.subscribe("on insert", Box::new(|_event, _patch| HookResult::Cancel));
+
+        let pushed = stack.push(Event::Insertion(Position { x: 0, y: 0 }, 'a'));
+
+        assert!(!pushed);
+        assert!(stack.commit());
+        assert!(stack.pop().is_none());
+    }
+
+    // HookResult::Enqueue must append its follow-up events after the triggering one
+    #[test]
+    fn enqueue_hook_appends_follow_up_events() {
+        let mut stack = EventStack::new();
+        stack.subscribe("on delete", Box::new(|_event, _patch| HookResult::Enqueue(vec![Event::Commit])));
+
+        let pushed = stack.push(Event::Deletion(Position { x: 0, y: 0 }, 'a'));
+        assert!(pushed);
+        stack.commit();
+
+        let patch = stack.pop().expect("should have committed a node");
+        assert_eq!(patch.len(), 2);
+    }
+
+    // With max_len(1), committing a second sibling branch should prune the other
+    // (least-recently-visited) one and report its banked event as evicted
+    #[test]
+    fn capacity_pruning_evicts_lru_leaf_and_its_banked_events() {
+        let mut stack = EventStack::new();
+        stack.set_max_len(Some(1));
+
+        stack.push(Event::Store(BankType::Line, 0));
+        stack.commit();
+        stack.pop();
+
+        stack.push(Event::Store(BankType::Line, 1));
+        stack.commit();
+
+        assert_eq!(stack.take_evicted_banks(), vec![(BankType::Line, 0)]);
+    }
+
+    // A saved history round-trips through the sidecar file: the coalesced run and the
+    // saved marker should both survive, proving the on-disk proxy types actually work
+    #[test]
+    fn history_round_trips_through_the_sidecar_file() {
+        let mut stack = EventStack::new_with_site(7);
+        stack.push(Event::Insertion(Position { x: 0, y: 0 }, 'a'));
+        stack.push(Event::Insertion(Position { x: 1, y: 0 }, 'b'));
+        stack.commit();
+        stack.mark_saved();
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("ox-undo-test-{}.txt", std::process::id()));
+        stack.save_history(&path, "document contents").expect("save_history should succeed");
+
+        let mut reloaded = EventStack::new();
+        let loaded = reloaded.load_history(&path, "document contents");
+        std::fs::remove_file(EventStack::sidecar_path(&path)).ok();
+
+        assert!(loaded);
+        assert!(!reloaded.is_modified());
+        let patch = reloaded.pop().expect("should have a node to undo back from");
+        match &patch[0] {
+            Event::InsertionRun(pos, run) => {
+                assert_eq!((pos.x, pos.y, run.as_str()), (0, 0, "ab"));
+            }
+            other => panic!("expected the coalesced run to survive persistence, got {other:?}"),
+        }
+    }
+}